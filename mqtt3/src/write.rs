@@ -1,7 +1,11 @@
+use async_trait::async_trait;
 use byteorder::{WriteBytesExt, BigEndian};
+use bytes::{BufMut, BytesMut};
 use error::{Error, Result};
-use std::io::{BufWriter, Write, Cursor};
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, Write, Cursor};
 use std::net::TcpStream;
+use tokio::io::AsyncWriteExt;
 use super::{PacketType, Header, QoS, Protocol, PacketIdentifier, MAX_PAYLOAD_SIZE};
 
 use mqtt::{
@@ -20,7 +24,12 @@ pub trait MqttWrite: WriteBytesExt {
             &Packet::Connect(ref connect) => {
                 try!(self.write_u8(0b00010000));
                 let prot_name = connect.protocol.name();
+                let is_v5 = connect.protocol.level() >= 5;
+                let prop_len = properties_len(&connect.properties);
                 let mut len = 8 + prot_name.len() + connect.client_id.len();
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
                 if let Some(ref last_will) = connect.last_will {
                     len += 4 + last_will.topic.len() + last_will.message.len();
                 }
@@ -52,6 +61,9 @@ pub trait MqttWrite: WriteBytesExt {
                 }
                 try!(self.write_u8(connect_flags));
                 try!(self.write_u16::<BigEndian>(connect.keep_alive));
+                if is_v5 {
+                    try!(self.write_properties(&connect.properties));
+                }
                 try!(self.write_mqtt_string(connect.client_id.as_ref()));
                 if let Some(ref last_will) = connect.last_will {
                     try!(self.write_mqtt_string(last_will.topic.as_ref()));
@@ -66,18 +78,39 @@ pub trait MqttWrite: WriteBytesExt {
                 Ok(())
             },
 			&Packet::Connack(ref connack) => {
-                try!(self.write(&[0x20, 0x02, connack.session_present as u8, connack.code.to_u8()]));
+                let is_v5 = connack.protocol.level() >= 5;
+                if is_v5 {
+                    let prop_len = properties_len(&connack.properties);
+                    try!(self.write(&[0x20]));
+                    try!(self.write_remaining_length(2 + varint_len(prop_len) + prop_len));
+                    try!(self.write(&[connack.session_present as u8, connack.code.to_u8()]));
+                    try!(self.write_properties(&connack.properties));
+                } else {
+                    try!(self.write(&[0x20, 0x02, connack.session_present as u8, connack.code.to_u8()]));
+                }
                 Ok(())
             },
 			&Packet::Publish(ref publish) => {
+                let is_v5 = publish.protocol.level() >= 5;
+                let prop_len = properties_len(&publish.properties);
                 try!(self.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3)));
-                try!(self.write_remaining_length(publish.topic_name.len() + 4 + publish.payload.len()));
+                let mut len = 2 + publish.topic_name.len() + publish.payload.len();
+                if publish.qos != QoS::AtMostOnce && publish.pid.is_some() {
+                    len += 2;
+                }
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
+                try!(self.write_remaining_length(len));
                 try!(self.write_mqtt_string(publish.topic_name.as_str()));
                 if publish.qos != QoS::AtMostOnce {
                     if let Some(pid) = publish.pid {
                         try!(self.write_u16::<BigEndian>(pid.0));
                     }
                 }
+                if is_v5 {
+                    try!(self.write_properties(&publish.properties));
+                }
                 try!(self.write(&publish.payload.as_ref()));
                 Ok(())
             },
@@ -86,22 +119,38 @@ pub trait MqttWrite: WriteBytesExt {
                 try!(self.write_u16::<BigEndian>(pid.0));
                 Ok(())
             },
-			&Packet::Pubrec(_) => Err(Error::UnsupportedPacketType),
+			&Packet::Pubrec(ref pid) => {
+                try!(self.write(&[0x50, 0x02]));
+                try!(self.write_u16::<BigEndian>(pid.0));
+                Ok(())
+            },
 			&Packet::Pubrel(ref pid) => {
                 try!(self.write(&[0x62, 0x02]));
                 try!(self.write_u16::<BigEndian>(pid.0));
                 Ok(())
             },
-			&Packet::Pubcomp(_) => Err(Error::UnsupportedPacketType),
+			&Packet::Pubcomp(ref pid) => {
+                try!(self.write(&[0x70, 0x02]));
+                try!(self.write_u16::<BigEndian>(pid.0));
+                Ok(())
+            },
 			&Packet::Subscribe(ref subscribe) => {
+                let is_v5 = subscribe.protocol.level() >= 5;
+                let prop_len = properties_len(&subscribe.properties);
                 try!(self.write(&[0x82]));
                 let mut len = 2;
                 let topics: &Vec<(String, QoS)> = subscribe.topics.as_ref();
                 for &(ref topic, _) in topics {
                     len += topic.len() + 3
                 }
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
                 try!(self.write_remaining_length(len));
                 try!(self.write_u16::<BigEndian>(subscribe.pid.0));
+                if is_v5 {
+                    try!(self.write_properties(&subscribe.properties));
+                }
                 for &(ref topic, ref qos) in topics {
                     try!(self.write_mqtt_string(topic.as_str()));
                     try!(self.write_u8(qos.to_u8()));
@@ -112,11 +161,24 @@ pub trait MqttWrite: WriteBytesExt {
                 try!(self.write(&[0x90]));
                 try!(self.write_remaining_length(suback.return_codes.len() + 2));
                 try!(self.write_u16::<BigEndian>(suback.pid.0));
-                let payload: Vec<u8> = suback.return_codes.iter().map({ |&(err, qos)| ((err as u8) << 7) & qos.to_u8() }).collect();
+                let payload: Vec<u8> = suback.return_codes.iter().map(SubscribeReturnCodes::to_u8).collect();
                 try!(self.write(&payload));
                 Ok(())
             },
-			&Packet::Unsubscribe(_) => Err(Error::UnsupportedPacketType),
+			&Packet::Unsubscribe(ref unsubscribe) => {
+                try!(self.write(&[0xA2]));
+                let mut len = 2;
+                let topics: &Vec<String> = unsubscribe.topics.as_ref();
+                for topic in topics {
+                    len += topic.len() + 2
+                }
+                try!(self.write_remaining_length(len));
+                try!(self.write_u16::<BigEndian>(unsubscribe.pid.0));
+                for topic in topics {
+                    try!(self.write_mqtt_string(topic.as_str()));
+                }
+                Ok(())
+            },
 			&Packet::Unsuback(ref pid) => {
                 try!(self.write(&[0xB0, 0x02]));
                 try!(self.write_u16::<BigEndian>(pid.0));
@@ -163,24 +225,653 @@ pub trait MqttWrite: WriteBytesExt {
 
         Ok(())
     }
+
+    fn write_properties(&mut self, properties: &[Property]) -> Result<()> {
+        try!(self.write_remaining_length(properties_len(properties)));
+        for property in properties {
+            try!(property.write(self));
+        }
+        Ok(())
+    }
+}
+
+/// MQTT 5 property, carried in the property block of v5 CONNECT/CONNACK/PUBLISH/SUBSCRIBE
+/// packets; no other packet is written with a property block. See MQTT-5.0 section
+/// 2.2.2.2 for the id/type table.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(usize),
+    SessionExpiryInterval(u32),
+    TopicAlias(u16),
+    UserProperty(String, String),
+}
+
+impl Property {
+    fn id(&self) -> u8 {
+        match *self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0B,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::TopicAlias(_) => 0x23,
+            Property::UserProperty(_, _) => 0x26,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Property::PayloadFormatIndicator(_) => 1,
+            Property::MessageExpiryInterval(_) => 4,
+            Property::SessionExpiryInterval(_) => 4,
+            Property::TopicAlias(_) => 2,
+            Property::ContentType(ref s) | Property::ResponseTopic(ref s) => 2 + s.len(),
+            Property::CorrelationData(ref d) => 2 + d.len(),
+            Property::SubscriptionIdentifier(id) => varint_len(id),
+            Property::UserProperty(ref k, ref v) => 2 + k.len() + 2 + v.len(),
+        }
+    }
+
+    fn write<W: MqttWrite + ?Sized>(&self, writer: &mut W) -> Result<()> {
+        try!(writer.write_u8(self.id()));
+        match *self {
+            Property::PayloadFormatIndicator(v) => try!(writer.write_u8(v)),
+            Property::MessageExpiryInterval(v) | Property::SessionExpiryInterval(v) => try!(writer.write_u32::<BigEndian>(v)),
+            Property::TopicAlias(v) => try!(writer.write_u16::<BigEndian>(v)),
+            Property::ContentType(ref s) | Property::ResponseTopic(ref s) => try!(writer.write_mqtt_string(s)),
+            Property::CorrelationData(ref d) => {
+                try!(writer.write_u16::<BigEndian>(d.len() as u16));
+                try!(writer.write(d));
+            },
+            Property::SubscriptionIdentifier(id) => try!(writer.write_remaining_length(id)),
+            Property::UserProperty(ref k, ref v) => {
+                try!(writer.write_mqtt_string(k));
+                try!(writer.write_mqtt_string(v));
+            },
+        }
+        Ok(())
+    }
+}
+
+fn properties_len(properties: &[Property]) -> usize {
+    properties.iter().map(Property::encoded_len).sum()
+}
+
+fn varint_len(mut len: usize) -> usize {
+    let mut count = 0;
+    loop {
+        len /= 128;
+        count += 1;
+        if len == 0 {
+            return count;
+        }
+    }
+}
+
+/// A single topic filter's outcome in a SUBACK, either the granted QoS or a failure
+/// (MQTT-3.9.3-2: return code `0x80`) reported back to the subscriber.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubscribeReturnCodes {
+    Success(QoS),
+    Failure
+}
+
+impl SubscribeReturnCodes {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            SubscribeReturnCodes::Success(qos) => qos.to_u8(),
+            SubscribeReturnCodes::Failure => 0x80
+        }
+    }
 }
 
 impl MqttWrite for TcpStream {}
 impl MqttWrite for Cursor<Vec<u8>> {}
 impl<T: Write> MqttWrite for BufWriter<T> {}
 
+/// The MQTT "remaining length" of `packet`: the size of its variable header and
+/// payload, excluding the fixed header byte and the remaining-length varint itself.
+/// Shared by `encode` (to emit the varint) and callers that want to pre-reserve a
+/// `BytesMut` of the right size up front.
+pub fn encoded_len(packet: &Packet) -> usize {
+    match packet {
+        &Packet::Connect(ref connect) => {
+            let is_v5 = connect.protocol.level() >= 5;
+            let mut len = 8 + connect.protocol.name().len() + connect.client_id.len();
+            if is_v5 {
+                let prop_len = properties_len(&connect.properties);
+                len += varint_len(prop_len) + prop_len;
+            }
+            if let Some(ref last_will) = connect.last_will {
+                len += 4 + last_will.topic.len() + last_will.message.len();
+            }
+            if let Some(ref username) = connect.username {
+                len += 2 + username.len();
+            }
+            if let Some(ref password) = connect.password {
+                len += 2 + password.len();
+            }
+            len
+        },
+        &Packet::Connack(ref connack) => {
+            if connack.protocol.level() >= 5 {
+                let prop_len = properties_len(&connack.properties);
+                2 + varint_len(prop_len) + prop_len
+            } else {
+                2
+            }
+        },
+        &Packet::Publish(ref publish) => {
+            let mut len = 2 + publish.topic_name.len() + publish.payload.len();
+            if publish.qos != QoS::AtMostOnce && publish.pid.is_some() {
+                len += 2;
+            }
+            if publish.protocol.level() >= 5 {
+                let prop_len = properties_len(&publish.properties);
+                len += varint_len(prop_len) + prop_len;
+            }
+            len
+        },
+        &Packet::Puback(_) | &Packet::Pubrec(_) | &Packet::Pubrel(_) | &Packet::Pubcomp(_) | &Packet::Unsuback(_) => 2,
+        &Packet::Subscribe(ref subscribe) => {
+            let mut len = 2 + subscribe.topics.iter().map(|&(ref topic, _)| topic.len() + 3).sum::<usize>();
+            if subscribe.protocol.level() >= 5 {
+                let prop_len = properties_len(&subscribe.properties);
+                len += varint_len(prop_len) + prop_len;
+            }
+            len
+        },
+        &Packet::Suback(ref suback) => 2 + suback.return_codes.len(),
+        &Packet::Unsubscribe(ref unsubscribe) => 2 + unsubscribe.topics.iter().map(|topic| topic.len() + 2).sum::<usize>(),
+        &Packet::Pingreq | &Packet::Pingresp | &Packet::Disconnect => 0
+    }
+}
+
+fn fixed_header_byte(packet: &Packet) -> u8 {
+    match packet {
+        &Packet::Connect(_) => 0b00010000,
+        &Packet::Connack(_) => 0x20,
+        &Packet::Publish(ref publish) => 0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3),
+        &Packet::Puback(_) => 0x40,
+        &Packet::Pubrec(_) => 0x50,
+        &Packet::Pubrel(_) => 0x62,
+        &Packet::Pubcomp(_) => 0x70,
+        &Packet::Subscribe(_) => 0x82,
+        &Packet::Suback(_) => 0x90,
+        &Packet::Unsubscribe(_) => 0xA2,
+        &Packet::Unsuback(_) => 0xB0,
+        &Packet::Pingreq => 0xc0,
+        &Packet::Pingresp => 0xd0,
+        &Packet::Disconnect => 0xe0
+    }
+}
+
+fn put_remaining_length(buf: &mut BytesMut, len: usize) -> Result<()> {
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(Error::PayloadTooLong);
+    }
+
+    let mut x = len;
+    loop {
+        let mut byte = (x % 128) as u8;
+        x /= 128;
+        if x > 0 {
+            byte |= 128;
+        }
+        buf.put_u8(byte);
+        if x == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn put_mqtt_string(buf: &mut BytesMut, string: &str) {
+    buf.put_u16(string.len() as u16);
+    buf.put_slice(string.as_bytes());
+}
+
+fn put_properties(buf: &mut BytesMut, properties: &[Property]) -> Result<()> {
+    try!(put_remaining_length(buf, properties_len(properties)));
+    for property in properties {
+        buf.put_u8(property.id());
+        match *property {
+            Property::PayloadFormatIndicator(v) => buf.put_u8(v),
+            Property::MessageExpiryInterval(v) | Property::SessionExpiryInterval(v) => buf.put_u32(v),
+            Property::TopicAlias(v) => buf.put_u16(v),
+            Property::ContentType(ref s) | Property::ResponseTopic(ref s) => put_mqtt_string(buf, s),
+            Property::CorrelationData(ref d) => {
+                buf.put_u16(d.len() as u16);
+                buf.put_slice(d);
+            },
+            Property::SubscriptionIdentifier(id) => try!(put_remaining_length(buf, id)),
+            Property::UserProperty(ref k, ref v) => {
+                put_mqtt_string(buf, k);
+                put_mqtt_string(buf, v);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Zero-copy counterpart to `MqttWrite::write_packet`: serializes straight into a
+/// `BytesMut` (reserved up front via `encoded_len`) instead of an intermediate stream,
+/// making this the natural building block for a Tokio `Encoder` codec.
+pub fn encode(packet: &Packet, buf: &mut BytesMut) -> Result<()> {
+    let len = encoded_len(packet);
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(Error::PayloadTooLong);
+    }
+    buf.reserve(1 + varint_len(len) + len);
+    buf.put_u8(fixed_header_byte(packet));
+    try!(put_remaining_length(buf, len));
+
+    match packet {
+        &Packet::Connect(ref connect) => {
+            let is_v5 = connect.protocol.level() >= 5;
+            put_mqtt_string(buf, connect.protocol.name());
+            buf.put_u8(connect.protocol.level());
+            let mut connect_flags = 0;
+            if connect.clean_session {
+                connect_flags |= 0x02;
+            }
+            if let Some(ref last_will) = connect.last_will {
+                connect_flags |= 0x04;
+                connect_flags |= last_will.qos.to_u8() << 3;
+                if last_will.retain {
+                    connect_flags |= 0x20;
+                }
+            }
+            if connect.password.is_some() {
+                connect_flags |= 0x40;
+            }
+            if connect.username.is_some() {
+                connect_flags |= 0x80;
+            }
+            buf.put_u8(connect_flags);
+            buf.put_u16(connect.keep_alive);
+            if is_v5 {
+                try!(put_properties(buf, &connect.properties));
+            }
+            put_mqtt_string(buf, connect.client_id.as_ref());
+            if let Some(ref last_will) = connect.last_will {
+                put_mqtt_string(buf, last_will.topic.as_ref());
+                put_mqtt_string(buf, last_will.message.as_ref());
+            }
+            if let Some(ref username) = connect.username {
+                put_mqtt_string(buf, username);
+            }
+            if let Some(ref password) = connect.password {
+                put_mqtt_string(buf, password);
+            }
+        },
+        &Packet::Connack(ref connack) => {
+            buf.put_u8(connack.session_present as u8);
+            buf.put_u8(connack.code.to_u8());
+            if connack.protocol.level() >= 5 {
+                try!(put_properties(buf, &connack.properties));
+            }
+        },
+        &Packet::Publish(ref publish) => {
+            put_mqtt_string(buf, publish.topic_name.as_str());
+            if publish.qos != QoS::AtMostOnce {
+                if let Some(pid) = publish.pid {
+                    buf.put_u16(pid.0);
+                }
+            }
+            if publish.protocol.level() >= 5 {
+                try!(put_properties(buf, &publish.properties));
+            }
+            buf.put_slice(publish.payload.as_ref());
+        },
+        &Packet::Puback(ref pid) | &Packet::Pubrec(ref pid) | &Packet::Pubrel(ref pid) |
+        &Packet::Pubcomp(ref pid) | &Packet::Unsuback(ref pid) => {
+            buf.put_u16(pid.0);
+        },
+        &Packet::Subscribe(ref subscribe) => {
+            buf.put_u16(subscribe.pid.0);
+            if subscribe.protocol.level() >= 5 {
+                try!(put_properties(buf, &subscribe.properties));
+            }
+            for &(ref topic, ref qos) in subscribe.topics.iter() {
+                put_mqtt_string(buf, topic.as_str());
+                buf.put_u8(qos.to_u8());
+            }
+        },
+        &Packet::Suback(ref suback) => {
+            buf.put_u16(suback.pid.0);
+            for return_code in suback.return_codes.iter() {
+                buf.put_u8(return_code.to_u8());
+            }
+        },
+        &Packet::Unsubscribe(ref unsubscribe) => {
+            buf.put_u16(unsubscribe.pid.0);
+            for topic in unsubscribe.topics.iter() {
+                put_mqtt_string(buf, topic.as_str());
+            }
+        },
+        &Packet::Pingreq | &Packet::Pingresp | &Packet::Disconnect => {}
+    }
+
+    Ok(())
+}
+
+/// Whether a `PacketWriter::write_ready` call drained every queued packet or stopped
+/// partway because the socket applied backpressure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStatus {
+    Ongoing,
+    Complete
+}
+
+/// Serializes packets up front and flushes them to a non-blocking `Write` a chunk at a
+/// time, so a `WouldBlock` mid-packet never corrupts the framing. Queue a packet with
+/// `write`, then call `write_ready` whenever the socket reports it's writable.
+pub struct PacketWriter {
+    queue: VecDeque<Cursor<Vec<u8>>>
+}
+
+impl PacketWriter {
+    pub fn new() -> PacketWriter {
+        PacketWriter { queue: VecDeque::new() }
+    }
+
+    pub fn write(&mut self, packet: &Packet) -> Result<()> {
+        let mut buf = Cursor::new(Vec::new());
+        try!(MqttWrite::write_packet(&mut buf, packet));
+        self.queue.push_back(buf);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn write_ready<W: Write>(&mut self, w: &mut W) -> Result<WriteStatus> {
+        loop {
+            let (done, result) = match self.queue.front_mut() {
+                Some(buf) => {
+                    let pos = buf.position() as usize;
+                    let data = &buf.get_ref()[pos..];
+                    match w.write(data) {
+                        Ok(n) => {
+                            buf.set_position((pos + n) as u64);
+                            (n == data.len(), Ok(()))
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(WriteStatus::Ongoing);
+                        },
+                        Err(e) => (false, Err(Error::from(e)))
+                    }
+                },
+                None => return Ok(WriteStatus::Complete)
+            };
+            try!(result);
+            if done {
+                self.queue.pop_front();
+            } else {
+                return Ok(WriteStatus::Ongoing);
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait AsyncMqttWrite: AsyncWriteExt + Unpin + Send {
+    async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        match packet {
+            &Packet::Connect(ref connect) => {
+                try!(self.write_u8(0b00010000).await);
+                let prot_name = connect.protocol.name();
+                let is_v5 = connect.protocol.level() >= 5;
+                let prop_len = properties_len(&connect.properties);
+                let mut len = 8 + prot_name.len() + connect.client_id.len();
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
+                if let Some(ref last_will) = connect.last_will {
+                    len += 4 + last_will.topic.len() + last_will.message.len();
+                }
+                if let Some(ref username) = connect.username {
+                    len += 2 + username.len();
+                }
+                if let Some(ref password) = connect.password {
+                    len += 2 + password.len();
+                }
+                try!(self.write_remaining_length(len).await);
+                try!(self.write_mqtt_string(prot_name).await);
+                try!(self.write_u8(connect.protocol.level()).await);
+                let mut connect_flags = 0;
+                if connect.clean_session {
+                    connect_flags |= 0x02;
+                }
+                if let Some(ref last_will) = connect.last_will {
+                    connect_flags |= 0x04;
+                    connect_flags |= last_will.qos.to_u8() << 3;
+                    if last_will.retain {
+                        connect_flags |= 0x20;
+                    }
+                }
+                if let Some(_) = connect.password {
+                    connect_flags |= 0x40;
+                }
+                if let Some(_) = connect.username {
+                    connect_flags |= 0x80;
+                }
+                try!(self.write_u8(connect_flags).await);
+                try!(self.write_u16(connect.keep_alive).await);
+                if is_v5 {
+                    try!(self.write_properties(&connect.properties).await);
+                }
+                try!(self.write_mqtt_string(connect.client_id.as_ref()).await);
+                if let Some(ref last_will) = connect.last_will {
+                    try!(self.write_mqtt_string(last_will.topic.as_ref()).await);
+                    try!(self.write_mqtt_string(last_will.message.as_ref()).await);
+                }
+                if let Some(ref username) = connect.username {
+                    try!(self.write_mqtt_string(username).await);
+                }
+                if let Some(ref password) = connect.password {
+                    try!(self.write_mqtt_string(password).await);
+                }
+                Ok(())
+            },
+            &Packet::Connack(ref connack) => {
+                if connack.protocol.level() >= 5 {
+                    let prop_len = properties_len(&connack.properties);
+                    try!(self.write_all(&[0x20]).await);
+                    try!(self.write_remaining_length(2 + varint_len(prop_len) + prop_len).await);
+                    try!(self.write_all(&[connack.session_present as u8, connack.code.to_u8()]).await);
+                    try!(self.write_properties(&connack.properties).await);
+                } else {
+                    try!(self.write_all(&[0x20, 0x02, connack.session_present as u8, connack.code.to_u8()]).await);
+                }
+                Ok(())
+            },
+            &Packet::Publish(ref publish) => {
+                let is_v5 = publish.protocol.level() >= 5;
+                let prop_len = properties_len(&publish.properties);
+                try!(self.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3)).await);
+                let mut len = 2 + publish.topic_name.len() + publish.payload.len();
+                if publish.qos != QoS::AtMostOnce && publish.pid.is_some() {
+                    len += 2;
+                }
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
+                try!(self.write_remaining_length(len).await);
+                try!(self.write_mqtt_string(publish.topic_name.as_str()).await);
+                if publish.qos != QoS::AtMostOnce {
+                    if let Some(pid) = publish.pid {
+                        try!(self.write_u16(pid.0).await);
+                    }
+                }
+                if is_v5 {
+                    try!(self.write_properties(&publish.properties).await);
+                }
+                try!(self.write_all(&publish.payload.as_ref()).await);
+                Ok(())
+            },
+            &Packet::Puback(ref pid) => {
+                try!(self.write_all(&[0x40, 0x02]).await);
+                try!(self.write_u16(pid.0).await);
+                Ok(())
+            },
+            &Packet::Pubrec(ref pid) => {
+                try!(self.write_all(&[0x50, 0x02]).await);
+                try!(self.write_u16(pid.0).await);
+                Ok(())
+            },
+            &Packet::Pubrel(ref pid) => {
+                try!(self.write_all(&[0x62, 0x02]).await);
+                try!(self.write_u16(pid.0).await);
+                Ok(())
+            },
+            &Packet::Pubcomp(ref pid) => {
+                try!(self.write_all(&[0x70, 0x02]).await);
+                try!(self.write_u16(pid.0).await);
+                Ok(())
+            },
+            &Packet::Subscribe(ref subscribe) => {
+                let is_v5 = subscribe.protocol.level() >= 5;
+                let prop_len = properties_len(&subscribe.properties);
+                try!(self.write_all(&[0x82]).await);
+                let mut len = 2;
+                let topics: &Vec<(String, QoS)> = subscribe.topics.as_ref();
+                for &(ref topic, _) in topics {
+                    len += topic.len() + 3
+                }
+                if is_v5 {
+                    len += varint_len(prop_len) + prop_len;
+                }
+                try!(self.write_remaining_length(len).await);
+                try!(self.write_u16(subscribe.pid.0).await);
+                if is_v5 {
+                    try!(self.write_properties(&subscribe.properties).await);
+                }
+                for &(ref topic, ref qos) in topics {
+                    try!(self.write_mqtt_string(topic.as_str()).await);
+                    try!(self.write_u8(qos.to_u8()).await);
+                }
+                Ok(())
+            },
+            &Packet::Suback(ref suback) => {
+                try!(self.write_all(&[0x90]).await);
+                try!(self.write_remaining_length(suback.return_codes.len() + 2).await);
+                try!(self.write_u16(suback.pid.0).await);
+                let payload: Vec<u8> = suback.return_codes.iter().map(SubscribeReturnCodes::to_u8).collect();
+                try!(self.write_all(&payload).await);
+                Ok(())
+            },
+            &Packet::Unsubscribe(ref unsubscribe) => {
+                try!(self.write_all(&[0xA2]).await);
+                let mut len = 2;
+                let topics: &Vec<String> = unsubscribe.topics.as_ref();
+                for topic in topics {
+                    len += topic.len() + 2
+                }
+                try!(self.write_remaining_length(len).await);
+                try!(self.write_u16(unsubscribe.pid.0).await);
+                for topic in topics {
+                    try!(self.write_mqtt_string(topic.as_str()).await);
+                }
+                Ok(())
+            },
+            &Packet::Unsuback(ref pid) => {
+                try!(self.write_all(&[0xB0, 0x02]).await);
+                try!(self.write_u16(pid.0).await);
+                Ok(())
+            },
+            &Packet::Pingreq => {
+                try!(self.write_all(&[0xc0, 0]).await);
+                Ok(())
+            },
+            &Packet::Pingresp => {
+                try!(self.write_all(&[0xd0, 0]).await);
+                Ok(())
+            },
+            &Packet::Disconnect => {
+                try!(self.write_all(&[0xe0, 0]).await);
+                Ok(())
+            }
+        }
+    }
+
+    async fn write_mqtt_string(&mut self, string: &str) -> Result<()> {
+        try!(self.write_u16(string.len() as u16).await);
+        try!(self.write_all(string.as_bytes()).await);
+        Ok(())
+    }
+
+    async fn write_remaining_length(&mut self, len: usize) -> Result<()> {
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(Error::PayloadTooLong);
+        }
+
+        let mut done = false;
+        let mut x = len;
+
+        while !done {
+            let mut byte = (x % 128) as u8;
+            x = x / 128;
+            if x > 0 {
+                byte = byte | 128;
+            }
+            try!(self.write_u8(byte).await);
+            done = x <= 0;
+        }
+
+        Ok(())
+    }
+
+    async fn write_properties(&mut self, properties: &[Property]) -> Result<()> {
+        try!(self.write_remaining_length(properties_len(properties)).await);
+        for property in properties {
+            try!(self.write_u8(property.id()).await);
+            match *property {
+                Property::PayloadFormatIndicator(v) => try!(self.write_u8(v).await),
+                Property::MessageExpiryInterval(v) | Property::SessionExpiryInterval(v) => try!(self.write_u32(v).await),
+                Property::TopicAlias(v) => try!(self.write_u16(v).await),
+                Property::ContentType(ref s) | Property::ResponseTopic(ref s) => try!(self.write_mqtt_string(s).await),
+                Property::CorrelationData(ref d) => {
+                    try!(self.write_u16(d.len() as u16).await);
+                    try!(self.write_all(d).await);
+                },
+                Property::SubscriptionIdentifier(id) => try!(self.write_remaining_length(id).await),
+                Property::UserProperty(ref k, ref v) => {
+                    try!(self.write_mqtt_string(k).await);
+                    try!(self.write_mqtt_string(v).await);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWriteExt + Unpin + Send> AsyncMqttWrite for W {}
+
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use std::io::{self, Cursor, Write};
     use std::sync::Arc;
-    use super::{MqttWrite};
+    use bytes::BytesMut;
+    use tokio::io::AsyncReadExt;
+    use super::{encode, encoded_len, MqttWrite, PacketWriter, Property, SubscribeReturnCodes, WriteStatus};
     use super::super::{Protocol, LastWill, QoS, PacketIdentifier, ConnectReturnCode};
     use super::super::mqtt::{
         Packet,
         Connect,
         Connack,
         Publish,
-        Subscribe
+        Subscribe,
+        Suback,
+        Unsubscribe
     };
 
     #[test]
@@ -197,7 +888,8 @@ mod test {
                 qos: QoS::AtLeastOnce
             }),
             username: Some("rust".to_owned()),
-            password: Some("mq".to_owned())
+            password: Some("mq".to_owned()),
+            properties: vec![]
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -225,7 +917,8 @@ mod test {
             clean_session: false,
             last_will: None,
             username: None,
-            password: None
+            password: None,
+            properties: vec![]
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -240,11 +933,40 @@ mod test {
         ]);
     }
 
+    #[test]
+    fn write_packet_connect_mqtt5_protocol_test() {
+        let connect = Packet::Connect(Arc::new(Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 60,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+            properties: vec![Property::SessionExpiryInterval(30)]
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&connect);
+
+        assert_eq!(stream.get_ref().clone(), vec![0x10, 22,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x05,
+            0b00000010, // -username, -password, -will retain, will qos=0, -last_will, +clean_session
+            0x00, 0x3c, // 60 sec
+            5, // property length
+            0x11, 0x00, 0x00, 0x00, 0x1e, // session expiry interval = 30
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8 // client_id
+        ]);
+    }
+
     #[test]
     fn write_packet_connack_test() {
         let connack = Packet::Connack(Connack {
+            protocol: Protocol::MQTT(4),
             session_present: true,
-            code: ConnectReturnCode::Accepted
+            code: ConnectReturnCode::Accepted,
+            properties: vec![]
         });
 
         let mut stream = Cursor::new(Vec::new());
@@ -256,12 +978,14 @@ mod test {
     #[test]
     fn write_packet_publish_test() {
         let publish = Packet::Publish(Arc::new(Publish {
+            protocol: Protocol::MQTT(4),
             dup: false,
             qos: QoS::AtLeastOnce,
             retain: false,
             topic_name: "a/b".to_owned(),
             pid: Some(PacketIdentifier(10)),
-            payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4])
+            payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4]),
+            properties: vec![]
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -273,12 +997,14 @@ mod test {
     #[test]
     fn write_packet_subscribe_test() {
         let subscribe = Packet::Subscribe(Arc::new(Subscribe {
+            protocol: Protocol::MQTT(4),
             pid: PacketIdentifier(260),
             topics: vec![
                 ("a/+".to_owned(), QoS::AtMostOnce),
                 ("#".to_owned(), QoS::AtLeastOnce),
                 ("a/b/c".to_owned(), QoS::ExactlyOnce)
-            ]
+            ],
+            properties: vec![]
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -294,4 +1020,256 @@ mod test {
             0x02 // qos = 2
         ]);
     }
+
+    #[test]
+    fn write_packet_pubrec_test() {
+        let pubrec = Packet::Pubrec(PacketIdentifier(10));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&pubrec);
+
+        assert_eq!(stream.get_ref().clone(), vec![0x50, 0x02, 0x00, 0x0a]);
+    }
+
+    #[test]
+    fn write_packet_pubcomp_test() {
+        let pubcomp = Packet::Pubcomp(PacketIdentifier(10));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&pubcomp);
+
+        assert_eq!(stream.get_ref().clone(), vec![0x70, 0x02, 0x00, 0x0a]);
+    }
+
+    #[test]
+    fn write_packet_unsubscribe_test() {
+        let unsubscribe = Packet::Unsubscribe(Arc::new(Unsubscribe {
+            pid: PacketIdentifier(260),
+            topics: vec!["a/+".to_owned(), "#".to_owned()]
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&unsubscribe);
+
+        assert_eq!(stream.get_ref().clone(), vec![0xA2, 10,
+            0x01, 0x04, // pid = 260
+            0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8, // topic filter = 'a/+'
+            0x00, 0x01, '#' as u8 // topic filter = '#'
+        ]);
+    }
+
+    #[test]
+    fn write_packet_suback_test() {
+        let suback = Packet::Suback(Arc::new(Suback {
+            pid: PacketIdentifier(10),
+            return_codes: vec![
+                SubscribeReturnCodes::Success(QoS::AtMostOnce),
+                SubscribeReturnCodes::Failure,
+                SubscribeReturnCodes::Success(QoS::ExactlyOnce)
+            ]
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&suback);
+
+        assert_eq!(stream.get_ref().clone(), vec![0x90, 5,
+            0x00, 0x0a, // pid = 10
+            0x00, // granted qos 0
+            0x80, // failure
+            0x02 // granted qos 2
+        ]);
+    }
+
+    /// Reports `WouldBlock` without writing anything on the first call, then accepts
+    /// the full buffer on every later call, simulating a socket that clears backpressure.
+    struct BlocksOnceWriter {
+        blocked: bool,
+        written: Vec<u8>
+    }
+
+    impl Write for BlocksOnceWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.blocked {
+                self.blocked = true;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_writer_completes_in_one_go_test() {
+        let pingreq = Packet::Pingreq;
+        let mut writer = PacketWriter::new();
+        writer.write(&pingreq).unwrap();
+
+        let mut sink = Cursor::new(Vec::new());
+        assert_eq!(writer.write_ready(&mut sink).unwrap(), WriteStatus::Complete);
+        assert!(writer.is_empty());
+        assert_eq!(sink.get_ref().clone(), vec![0xc0, 0]);
+    }
+
+    #[test]
+    fn packet_writer_resumes_across_would_block_test() {
+        let puback = Packet::Puback(PacketIdentifier(10));
+        let mut writer = PacketWriter::new();
+        writer.write(&puback).unwrap();
+
+        let mut socket = BlocksOnceWriter { blocked: false, written: Vec::new() };
+        assert_eq!(writer.write_ready(&mut socket).unwrap(), WriteStatus::Ongoing);
+        assert!(!writer.is_empty());
+        assert_eq!(writer.write_ready(&mut socket).unwrap(), WriteStatus::Complete);
+        assert!(writer.is_empty());
+        assert_eq!(socket.written, vec![0x40, 0x02, 0x00, 0x0a]);
+    }
+
+    #[test]
+    fn encode_publish_qos0_matches_encoded_len_test() {
+        let publish = Packet::Publish(Arc::new(Publish {
+            protocol: Protocol::MQTT(4),
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4]),
+            properties: vec![]
+        }));
+
+        assert_eq!(encoded_len(&publish), 3 + 2 + 4);
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&publish);
+
+        let mut buf = BytesMut::new();
+        encode(&publish, &mut buf);
+
+        assert_eq!(&buf[..], vec![0b00110000, 9, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0xF1, 0xF2, 0xF3, 0xF4].as_slice());
+        assert_eq!(&buf[..], stream.get_ref().as_slice());
+    }
+
+    #[test]
+    fn encode_subscribe_test() {
+        let subscribe = Packet::Subscribe(Arc::new(Subscribe {
+            protocol: Protocol::MQTT(4),
+            pid: PacketIdentifier(260),
+            topics: vec![
+                ("a/+".to_owned(), QoS::AtMostOnce),
+                ("#".to_owned(), QoS::AtLeastOnce),
+                ("a/b/c".to_owned(), QoS::ExactlyOnce)
+            ],
+            properties: vec![]
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&subscribe);
+
+        let mut buf = BytesMut::new();
+        encode(&subscribe, &mut buf);
+
+        assert_eq!(&buf[..], stream.get_ref().as_slice());
+    }
+
+    async fn write_packet_async(packet: &Packet) -> Vec<u8> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        super::AsyncMqttWrite::write_packet(&mut client, packet).await.unwrap();
+        drop(client);
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn async_write_packet_connect_mqtt5_protocol_test() {
+        let connect = Packet::Connect(Arc::new(Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 60,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+            properties: vec![Property::SessionExpiryInterval(30)]
+        }));
+
+        let buf = write_packet_async(&connect).await;
+
+        assert_eq!(buf, vec![0x10, 22,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x05,
+            0b00000010, // -username, -password, -will retain, will qos=0, -last_will, +clean_session
+            0x00, 0x3c, // 60 sec
+            5, // property length
+            0x11, 0x00, 0x00, 0x00, 0x1e, // session expiry interval = 30
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8 // client_id
+        ]);
+    }
+
+    #[tokio::test]
+    async fn async_write_packet_publish_qos0_test() {
+        let publish = Packet::Publish(Arc::new(Publish {
+            protocol: Protocol::MQTT(4),
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4]),
+            properties: vec![]
+        }));
+
+        let buf = write_packet_async(&publish).await;
+
+        assert_eq!(buf, vec![0b00110000, 9, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0xF1, 0xF2, 0xF3, 0xF4]);
+    }
+
+    #[tokio::test]
+    async fn async_write_packet_subscribe_test() {
+        let subscribe = Packet::Subscribe(Arc::new(Subscribe {
+            protocol: Protocol::MQTT(4),
+            pid: PacketIdentifier(260),
+            topics: vec![
+                ("a/+".to_owned(), QoS::AtMostOnce),
+                ("#".to_owned(), QoS::AtLeastOnce),
+                ("a/b/c".to_owned(), QoS::ExactlyOnce)
+            ],
+            properties: vec![]
+        }));
+
+        let buf = write_packet_async(&subscribe).await;
+
+        assert_eq!(buf, vec![0b10000010, 20,
+            0x01, 0x04, // pid = 260
+            0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8, // topic filter = 'a/+'
+            0x00, // qos = 0
+            0x00, 0x01, '#' as u8, // topic filter = '#'
+            0x01, // qos = 1
+            0x00, 0x05, 'a' as u8, '/' as u8, 'b' as u8, '/' as u8, 'c' as u8, // topic filter = 'a/b/c'
+            0x02 // qos = 2
+        ]);
+    }
+
+    #[tokio::test]
+    async fn async_write_packet_pubrec_pubcomp_unsubscribe_test() {
+        let pubrec = Packet::Pubrec(PacketIdentifier(10));
+        assert_eq!(write_packet_async(&pubrec).await, vec![0x50, 0x02, 0x00, 0x0a]);
+
+        let pubcomp = Packet::Pubcomp(PacketIdentifier(10));
+        assert_eq!(write_packet_async(&pubcomp).await, vec![0x70, 0x02, 0x00, 0x0a]);
+
+        let unsubscribe = Packet::Unsubscribe(Arc::new(Unsubscribe {
+            pid: PacketIdentifier(260),
+            topics: vec!["a/+".to_owned(), "#".to_owned()]
+        }));
+        assert_eq!(write_packet_async(&unsubscribe).await, vec![0xA2, 10,
+            0x01, 0x04, // pid = 260
+            0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8, // topic filter = 'a/+'
+            0x00, 0x01, '#' as u8 // topic filter = '#'
+        ]);
+    }
 }